@@ -1,19 +1,29 @@
 use std::{
     collections::HashSet,
     path::{Path, PathBuf},
+    sync::Arc,
     time::Duration,
 };
 
-use anyhow::{bail, Context, Result};
-use hyper::{Client, StatusCode};
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{client::HttpConnector, Body, Client, StatusCode};
 use hyper_tls::HttpsConnector;
 use regex::Regex;
 use tokio::{
     sync::mpsc::{self, UnboundedSender},
+    sync::Semaphore,
+    task::JoinSet,
     time::sleep,
 };
 
-use crate::git_parsing::{parse_hash, parse_head, parse_log, parse_object, GitObject};
+/// The shared `hyper` client reused across every request of a dump.
+pub(crate) type SharedClient = Client<HttpsConnector<HttpConnector>, Body>;
+
+use crate::git_parsing::{
+    check_path_traversal, parse_config, parse_hash, parse_head, parse_index, parse_log,
+    parse_object, parse_object_body, parse_packed_refs, GitObject,
+};
+use crate::{smart_http, Args, Mode};
 
 lazy_static::lazy_static! {
     static ref REGEX_OBJECT_PATH: Regex = Regex::new(r"[\da-f]{2}/[\da-f]{38}").unwrap();
@@ -42,16 +52,463 @@ struct DownloadedFile {
     pub tx: UnboundedSender<DownloadedFile>,
 }
 
-pub async fn download_all(base_url: String, base_path: PathBuf, max_task_count: u16) {
+pub async fn download_all(args: Arc<Args>) {
+    let base_url = args.url.clone();
+    let base_path = args.path.clone();
+
+    // build the client once and share it across every request
+    let client: SharedClient = Client::builder().build(HttpsConnector::new());
+
+    match args.mode {
+        Mode::Dumb => download_dumb(&client, &args, base_url, base_path).await,
+        Mode::Smart => {
+            if let Err(e) = download_smart(&client, &base_url, &base_path).await {
+                println!("Smart-HTTP dump failed: {e}");
+            }
+        }
+        Mode::Auto => {
+            // prefer the smart endpoint (it recovers repos that block raw file
+            // access) and fall back to crawling the exposed directory
+            match download_smart(&client, &base_url, &base_path).await {
+                Ok(()) => {}
+                Err(e) => {
+                    println!("Smart-HTTP dump unavailable ({e}), falling back to dumb crawl");
+                    download_dumb(&client, &args, base_url.clone(), base_path.clone()).await;
+                }
+            }
+        }
+    }
+
+    // explode any recovered packs into loose objects so the reconstruction
+    // below (which reads loose objects) can see pack-only history
+    explode_packs(&base_path);
+
+    // once the objects are on disk, rebuild the working tree so the user does
+    // not have to run a `git checkout` that would fail on any missing object
+    if let Err(e) = checkout(&base_path) {
+        println!("Could not reconstruct the working tree: {e}");
+    }
+}
+
+/// Decode every `objects/pack/*.pack` on disk and write its objects out as
+/// loose objects, so [`read_loose_object`] can resolve pack-only history.
+fn explode_packs(base_path: &Path) {
+    let pack_dir = base_path.join(".git").join("objects").join("pack");
+    let entries = match std::fs::read_dir(&pack_dir) {
+        Ok(entries) => entries,
+        Err(_) => return,
+    };
+
+    for entry in entries.flatten() {
+        let path = entry.path();
+        if path.extension().and_then(|e| e.to_str()) != Some("pack") {
+            continue;
+        }
+
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Could not read pack {}: {e}", path.to_string_lossy());
+                continue;
+            }
+        };
+        let decoded = match crate::packfile::decode_pack(&data) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                println!("Could not decode pack {}: {e}", path.to_string_lossy());
+                continue;
+            }
+        };
+
+        let mut written = 0;
+        for obj in decoded.objects {
+            match write_loose_object(base_path, &obj) {
+                Ok(true) => written += 1,
+                Ok(false) => {}
+                Err(e) => println!("Could not write loose object {}: {e}", obj.hash),
+            }
+        }
+        println!("Exploded {written} new objects from {}", path.to_string_lossy());
+    }
+}
+
+/// Write a reconstructed pack object to disk in loose `objects/xx/yy` form,
+/// returning whether it was newly written (an already-present object is left
+/// untouched).
+fn write_loose_object(base_path: &Path, obj: &crate::packfile::PackObject) -> Result<bool> {
+    let dir = base_path.join(".git").join("objects").join(&obj.hash[0..2]);
+    let target = dir.join(&obj.hash[2..]);
+    if target.exists() {
+        return Ok(false);
+    }
+
+    let mut store = format!("{} {}\0", obj.kind, obj.data.len()).into_bytes();
+    store.extend_from_slice(&obj.data);
+    let compressed = miniz_oxide::deflate::compress_to_vec_zlib(&store, 6);
+
+    std::fs::create_dir_all(&dir)
+        .with_context(|| format!("Could not create {}", dir.to_string_lossy()))?;
+    std::fs::write(&target, &compressed)
+        .with_context(|| format!("Could not write object {}", obj.hash))?;
+
+    Ok(true)
+}
+
+/// Walk the commit at `HEAD` down through its trees and write every recovered
+/// blob into the output directory at its recorded path and mode.
+///
+/// Entries whose objects were never recovered are skipped and reported rather
+/// than aborting the whole checkout.
+fn checkout(base_path: &Path) -> Result<()> {
+    let git = base_path.join(".git");
+
+    let head = std::fs::read(git.join("HEAD")).context("Could not read HEAD")?;
+    // HEAD is usually a symbolic ref; fall back to a detached commit hash
+    let commit_hash = match parse_head(&head) {
+        Ok(ref_path) => resolve_ref(&git, ref_path)?,
+        Err(_) => parse_hash(&head)?.to_string(),
+    };
+
+    let (kind, body) = read_loose_object(&git, &commit_hash)?;
+    if kind != "commit" {
+        bail!("HEAD does not point at a commit (found {kind})");
+    }
+
+    let tree_hash = String::from_utf8_lossy(&body)
+        .lines()
+        .find_map(|line| line.strip_prefix("tree ").map(str::to_string))
+        .ok_or_else(|| anyhow!("HEAD commit has no tree"))?;
+
+    println!("Reconstructing working tree from commit {commit_hash}");
+    let mut missing = 0;
+    checkout_tree(&git, base_path, &tree_hash, &mut missing)?;
+
+    // the index often lists blobs that no recovered tree names; place those too
+    checkout_index(&git, base_path, &mut missing);
+
+    if missing > 0 {
+        println!("\t{missing} object(s) were missing and could not be written");
+    }
+
+    Ok(())
+}
+
+/// Write any tracked blob named by `.git/index` that the tree walk did not
+/// already place, recovering files even when no reachable tree references them.
+fn checkout_index(git: &Path, base_path: &Path, missing: &mut u32) {
+    let index = match std::fs::read(git.join("index")) {
+        Ok(data) => data,
+        Err(_) => return,
+    };
+    let entries = match parse_index(&index) {
+        Ok(entries) => entries,
+        Err(e) => {
+            println!("Could not parse index: {e}");
+            return;
+        }
+    };
+
+    for entry in entries {
+        if check_path_traversal(&entry.path).is_err() {
+            continue;
+        }
+        let target = base_path.join(&entry.path);
+        if target.exists() {
+            continue;
+        }
+
+        match read_loose_object(git, &entry.hash) {
+            Ok((_, blob)) => {
+                if let Some(parent) = target.parent() {
+                    let _ = std::fs::create_dir_all(parent);
+                }
+                // the index encodes the object type in the top nibble of mode
+                if entry.mode & 0xf000 == 0xa000 {
+                    if let Err(e) = write_symlink(&target, &blob) {
+                        println!("\t{e}");
+                    }
+                } else if let Err(e) = std::fs::write(&target, &blob) {
+                    println!("\tCould not write {}: {e}", entry.path);
+                } else if entry.mode & 0o111 != 0 {
+                    set_mode(&target, "100755");
+                }
+            }
+            Err(_) => {
+                *missing += 1;
+                println!("\tMissing object for {}", entry.path);
+            }
+        }
+    }
+}
+
+/// Resolve a ref to its commit hash, first as a loose ref file and then by
+/// scanning `packed-refs` (which is where many servers keep the real refs).
+fn resolve_ref(git: &Path, ref_path: &str) -> Result<String> {
+    if let Ok(data) = std::fs::read(git.join(ref_path)) {
+        return Ok(parse_hash(&data)?.to_string());
+    }
+
+    let packed = std::fs::read_to_string(git.join("packed-refs"))
+        .with_context(|| format!("Could not resolve ref {ref_path}"))?;
+    for line in packed.lines() {
+        if line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+        if let Some((hash, name)) = line.split_once(' ') {
+            if name == ref_path {
+                return Ok(hash.to_string());
+            }
+        }
+    }
+
+    bail!("Could not find ref {ref_path} in packed-refs")
+}
+
+/// Recursively write a tree object into `dir`, recovering sub-trees and blobs.
+fn checkout_tree(git: &Path, dir: &Path, tree_hash: &str, missing: &mut u32) -> Result<()> {
+    let (kind, body) = match read_loose_object(git, tree_hash) {
+        Ok(obj) => obj,
+        Err(_) => {
+            *missing += 1;
+            return Ok(());
+        }
+    };
+    if kind != "tree" {
+        bail!("Expected a tree object for {tree_hash}, found {kind}");
+    }
+
+    let GitObject::Tree(entries) = parse_object_body("tree", &body)? else {
+        bail!("Object {tree_hash} did not parse as a tree");
+    };
+
+    for entry in entries {
+        // guard every name the same way we guard the HEAD ref path
+        check_path_traversal(&entry.name)?;
+        let target = dir.join(&entry.name);
+
+        match entry.mode.as_str() {
+            "40000" => {
+                std::fs::create_dir_all(&target).with_context(|| {
+                    format!("Could not create directory {}", target.to_string_lossy())
+                })?;
+                checkout_tree(git, &target, &entry.hash, missing)?;
+            }
+            // a gitlink points at a commit in a submodule we never dumped
+            "160000" => {
+                println!("\tSkipping submodule {}", entry.name);
+            }
+            mode => match read_loose_object(git, &entry.hash) {
+                Ok((_, blob)) => {
+                    if mode == "120000" {
+                        write_symlink(&target, &blob)?;
+                    } else {
+                        std::fs::write(&target, &blob).with_context(|| {
+                            format!("Could not write file {}", target.to_string_lossy())
+                        })?;
+                        set_mode(&target, mode);
+                    }
+                }
+                Err(_) => {
+                    *missing += 1;
+                    println!("\tMissing object for {}", entry.name);
+                }
+            },
+        }
+    }
+
+    Ok(())
+}
+
+/// Read a loose object from the recovered `.git` directory, returning its type
+/// keyword and the body after the `"<type> <len>\0"` header.
+fn read_loose_object(git: &Path, hash: &str) -> Result<(String, Vec<u8>)> {
+    if hash.len() != 40 || !hash.bytes().all(|b| b.is_ascii_hexdigit()) {
+        bail!("Malformed object hash: {hash:?}");
+    }
+    let path = git.join("objects").join(&hash[0..2]).join(&hash[2..]);
+    let raw = std::fs::read(&path).with_context(|| format!("Could not read object {hash}"))?;
+    let decompressed = miniz_oxide::inflate::decompress_to_vec_zlib(&raw)
+        .map_err(|e| anyhow!("Could not decompress object {hash}: {e}"))?;
+
+    let nul = decompressed
+        .iter()
+        .position(|&b| b == b'\0')
+        .ok_or_else(|| anyhow!("Object {hash} has no header separator"))?;
+    let header = String::from_utf8_lossy(&decompressed[..nul]);
+    let kind = header
+        .split(' ')
+        .next()
+        .ok_or_else(|| anyhow!("Object {hash} has an empty header"))?
+        .to_string();
+
+    Ok((kind, decompressed[nul + 1..].to_vec()))
+}
+
+/// Apply a tree entry's octal mode to the written file where the platform
+/// supports it (currently the executable bit on unix).
+#[cfg(unix)]
+fn set_mode(path: &Path, mode: &str) {
+    use std::os::unix::fs::PermissionsExt;
+
+    let perms = if mode == "100755" { 0o755 } else { 0o644 };
+    if let Err(e) = std::fs::set_permissions(path, std::fs::Permissions::from_mode(perms)) {
+        println!("\tCould not set mode on {}: {e}", path.to_string_lossy());
+    }
+}
+
+#[cfg(not(unix))]
+fn set_mode(_path: &Path, _mode: &str) {}
+
+/// Write a symlink whose target is the blob's content, falling back to a plain
+/// file on platforms without symlink support.
+#[cfg(unix)]
+fn write_symlink(path: &Path, blob: &[u8]) -> Result<()> {
+    let target = String::from_utf8_lossy(blob);
+    std::os::unix::fs::symlink(target.as_ref(), path)
+        .with_context(|| format!("Could not create symlink {}", path.to_string_lossy()))
+}
+
+#[cfg(not(unix))]
+fn write_symlink(path: &Path, blob: &[u8]) -> Result<()> {
+    std::fs::write(path, blob)
+        .with_context(|| format!("Could not write file {}", path.to_string_lossy()))
+}
+
+/// Recover a repository through the `git-upload-pack` smart-HTTP endpoint by
+/// discovering the advertised refs, requesting a packfile for all of them and
+/// decoding it.
+async fn download_smart(client: &SharedClient, base_url: &str, base_path: &Path) -> Result<()> {
+    let advertisement = smart_http::discover_refs(client, base_url).await?;
+    if advertisement.refs.is_empty() {
+        bail!("Server advertised no refs");
+    }
+    println!(
+        "Discovered {} refs via smart-HTTP",
+        advertisement.refs.len()
+    );
+
+    // collect the unique target of every advertised ref
+    let mut wants: Vec<String> = advertisement.refs.iter().map(|r| r.hash.clone()).collect();
+    wants.sort();
+    wants.dedup();
+
+    let pack = smart_http::fetch_pack(client, base_url, &wants, &advertisement.capabilities).await?;
+    println!("Received packfile ({} bytes)", pack.len());
+
+    if pack.len() < 20 {
+        bail!("Packfile is too small to contain a trailer checksum");
+    }
+
+    // name the pack after its trailing SHA-1 checksum so it follows git's
+    // `pack-<sha>.pack` convention alongside the loose objects a dumb dump
+    // would have produced
+    let pack_hash = crate::git_parsing::slice_to_hex(&pack[pack.len() - 20..]);
+    write_file(
+        base_path,
+        &format!("objects/pack/pack-{pack_hash}.pack"),
+        &pack,
+    )?;
+
+    // any hashes left over are bases that live outside the pack; warn rather
+    // than silently dropping them
+    let missing = crate::packfile::parse_pack(&pack)?;
+    if !missing.is_empty() {
+        println!(
+            "\t{} referenced objects are not contained in the pack",
+            missing.len()
+        );
+    }
+
+    // the pack carries only objects; without an entry point checkout has
+    // nothing to walk, so materialise packed-refs and HEAD from the
+    // advertisement
+    write_refs_from_advertisement(base_path, &advertisement)?;
+
+    Ok(())
+}
+
+/// Reconstruct `packed-refs` and `HEAD` from a smart-HTTP advertisement so the
+/// recovered repository has an entry point for checkout.
+fn write_refs_from_advertisement(
+    base_path: &Path,
+    advertisement: &smart_http::Advertisement,
+) -> Result<()> {
+    // we never compute peel (`^{}`) lines, so emit no pack-refs traits header:
+    // claiming `peeled` would wrongly assert peel lines are present
+    let mut packed = String::new();
+    for r in &advertisement.refs {
+        // HEAD is recorded as a symbolic ref below, not as a packed ref
+        if r.name == "HEAD" {
+            continue;
+        }
+        packed.push_str(&format!("{} {}\n", r.hash, r.name));
+    }
+    write_file(base_path, "packed-refs", packed.as_bytes())?;
+
+    // servers announce HEAD's target via the `symref=HEAD:<ref>` capability;
+    // only honour it when that branch is actually packed (otherwise resolve_ref
+    // would dangle), then fall back to any branch.
+    let branch = advertisement
+        .capabilities
+        .iter()
+        .find_map(|c| c.strip_prefix("symref=HEAD:"))
+        .filter(|target| advertisement.refs.iter().any(|r| &r.name == target))
+        .or_else(|| {
+            advertisement
+                .refs
+                .iter()
+                .find(|r| r.name.starts_with("refs/heads/"))
+                .map(|r| r.name.as_str())
+        });
+    let head = if let Some(branch) = branch {
+        format!("ref: {branch}\n")
+    } else {
+        // no branch to point at: detach onto the ref literally named HEAD (a
+        // real commit), else the first advertised ref, so a recovered pack is
+        // never discarded just because HEAD is unusual
+        let detached = advertisement
+            .refs
+            .iter()
+            .find(|r| r.name == "HEAD")
+            .or_else(|| advertisement.refs.first())
+            .ok_or_else(|| anyhow!("Advertisement carried no refs"))?;
+        format!("{}\n", detached.hash)
+    };
+    write_file(base_path, "HEAD", head.as_bytes())?;
+
+    Ok(())
+}
+
+/// Running totals reported once a dumb dump finishes.
+#[derive(Debug, Default)]
+struct DownloadStats {
+    downloaded: u32,
+    failed: u32,
+    skipped: u32,
+    absent: u32,
+}
+
+/// The outcome of a single download task, used to tally [`DownloadStats`].
+enum TaskOutcome {
+    Downloaded,
+    Failed,
+    /// The server returned 404 — the file simply does not exist.
+    Absent,
+}
+
+async fn download_dumb(
+    client: &SharedClient,
+    args: &Args,
+    base_url: String,
+    base_path: PathBuf,
+) {
     let mut cache = HashSet::<String>::new();
+    let mut stats = DownloadStats::default();
+    let max_task_count = args.tasks;
 
-    // TODO: try out unbounded channel too
-    // TODO: maybe just have a cli option that determines the limit of concurrent downloads instead?
     let (tx, mut rx) = mpsc::unbounded_channel();
 
     for &file in START_FILES {
-        // let new_tx = tx.clone();
-        // cache.download(file, new_tx);
         tx.send(DownloadedFile {
             path: file.into(),
             tx: tx.clone(),
@@ -62,25 +519,41 @@ pub async fn download_all(base_url: String, base_path: PathBuf, max_task_count:
     // drop the sender object so all senders can be out of scope by the end of the download
     drop(tx);
 
+    // bound concurrency with a semaphore instead of busy-waiting, and keep the
+    // spawned handles in a JoinSet so their results (and any panics) surface
+    let semaphore = Arc::new(Semaphore::new((max_task_count.max(1)) as usize));
+    let mut tasks = JoinSet::<TaskOutcome>::new();
+
     // every time we downloaded a new file, see what other files we can derive from it
-    let mut threads = vec![];
     while let Some(message) = rx.recv().await {
-        // TODO: if this file is already downloaded, continue
         if cache.contains(&message.path) {
-            // println!("Skipping download of file {file_name} as it's already downloaded");
+            stats.skipped += 1;
             continue;
         }
-
         cache.insert(message.path.clone());
 
+        // acquire a permit before spawning so the queue applies real
+        // backpressure; the permit is released when the task's future drops
+        let permit = semaphore
+            .clone()
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+
         let url = format!("{}{}", &base_url, &message.path);
         let base_path = base_path.clone();
-        let handle = tokio::spawn(async move {
-            let file_bytes = match download(&url).await {
-                Ok(content) => content,
+        let client = client.clone();
+        let retries = args.retries;
+        let backoff_ms = args.retry_backoff_ms;
+        tasks.spawn(async move {
+            let _permit = permit;
+
+            let file_bytes = match download(&client, &url, retries, backoff_ms).await {
+                Ok(Some(content)) => content,
+                Ok(None) => return TaskOutcome::Absent,
                 Err(e) => {
                     println!("Error while downloading file {url}: {}", e);
-                    return;
+                    return TaskOutcome::Failed;
                 }
             };
 
@@ -88,52 +561,122 @@ pub async fn download_all(base_url: String, base_path: PathBuf, max_task_count:
 
             // write this file to disk
             if let Err(e) = write_file(&base_path, &message.path, &file_bytes) {
-                println!("Failed to write file {} to disk: {}", &message.path, e)
+                println!("Failed to write file {} to disk: {}", &message.path, e);
+                return TaskOutcome::Failed;
             }
 
             // match on the file name and queue new messages
             if let Err(e) = queue_new_references(message.path.as_str(), &file_bytes, message.tx) {
                 println!("Error while trying to find new references: {e}");
             }
+
+            TaskOutcome::Downloaded
         });
 
-        threads.push(handle);
+        // reap whatever has already finished without blocking the receive loop
+        while let Some(result) = tasks.try_join_next() {
+            tally(result, &mut stats);
+        }
+    }
 
-        while threads.len() >= (max_task_count as usize) {
-            // sleep
-            sleep(Duration::from_millis(10)).await;
+    // the channel only closes once every task has dropped its sender, so drain
+    // any stragglers still in flight
+    while let Some(result) = tasks.join_next().await {
+        tally(result, &mut stats);
+    }
+
+    println!(
+        "Done: {} downloaded, {} failed, {} skipped, {} absent",
+        stats.downloaded, stats.failed, stats.skipped, stats.absent
+    );
+}
 
-            // remove dead threads
-            threads.retain(|h| !h.is_finished());
+fn tally(result: Result<TaskOutcome, tokio::task::JoinError>, stats: &mut DownloadStats) {
+    match result {
+        Ok(TaskOutcome::Downloaded) => stats.downloaded += 1,
+        Ok(TaskOutcome::Absent) => stats.absent += 1,
+        Ok(TaskOutcome::Failed) => stats.failed += 1,
+        Err(e) => {
+            println!("Download task panicked: {e}");
+            stats.failed += 1;
         }
     }
 }
 
-async fn download(url: &str) -> Result<Vec<u8>> {
-    let client = Client::builder().build::<_, hyper::Body>(HttpsConnector::new());
-    let resp = client.get(url.parse().unwrap()).await;
-    match resp {
-        Ok(resp) => match resp.status() {
-            StatusCode::OK => {
-                let bytes = hyper::body::to_bytes(resp).await.unwrap();
-                Ok(bytes.to_vec())
-            }
-            StatusCode::NOT_FOUND => {
-                bail!("Got 404 while trying to download {url}")
-            }
-            _ => {
-                bail!(
-                    "Error while trying to download {url}: status code is {}",
-                    resp.status()
-                )
+/// Download a single URL with the shared client, retrying transient failures.
+///
+/// Returns `Ok(Some(bytes))` on success, `Ok(None)` when the server reports the
+/// object as absent (404, not retried), and `Err` once the retry budget is
+/// exhausted. Connection errors and 429/5xx responses are retried up to
+/// `retries` times with exponential backoff, honoring a `Retry-After` header
+/// when the server sends one.
+async fn download(
+    client: &SharedClient,
+    url: &str,
+    retries: u32,
+    backoff_ms: u64,
+) -> Result<Option<Vec<u8>>> {
+    let uri: hyper::Uri = url.parse().with_context(|| format!("Invalid URL {url}"))?;
+
+    let mut attempt = 0;
+    loop {
+        match client.get(uri.clone()).await {
+            Ok(resp) => match resp.status() {
+                StatusCode::OK => {
+                    let bytes = hyper::body::to_bytes(resp)
+                        .await
+                        .with_context(|| format!("Could not read body of {url}"))?;
+                    return Ok(Some(bytes.to_vec()));
+                }
+                // a missing object is a fast, definitive answer: do not retry
+                StatusCode::NOT_FOUND => return Ok(None),
+                status
+                    if status == StatusCode::TOO_MANY_REQUESTS || status.is_server_error() =>
+                {
+                    if attempt >= retries {
+                        bail!("Giving up on {url} after {retries} retries (status {status})");
+                    }
+                    let wait = retry_after(&resp)
+                        .unwrap_or_else(|| backoff_delay(backoff_ms, attempt));
+                    println!("Transient status {status} for {url}, retrying in {wait:?}");
+                    sleep(wait).await;
+                    attempt += 1;
+                }
+                // any other status (e.g. 403, 401) is treated as permanent
+                status => bail!("Error while trying to download {url}: status code is {status}"),
+            },
+            Err(e) => {
+                if attempt >= retries {
+                    bail!("Giving up on {url} after {retries} retries: {e}");
+                }
+                let wait = backoff_delay(backoff_ms, attempt);
+                println!("Connection error for {url} ({e}), retrying in {wait:?}");
+                sleep(wait).await;
+                attempt += 1;
             }
-        },
-        Err(e) => {
-            bail!("Error while trying to download {url}: {e}");
         }
     }
 }
 
+/// Compute the exponential backoff delay for a given attempt.
+fn backoff_delay(backoff_ms: u64, attempt: u32) -> Duration {
+    Duration::from_millis(backoff_ms.saturating_mul(1u64 << attempt.min(16)))
+}
+
+/// Parse a `Retry-After` header expressed as an integer number of seconds.
+fn retry_after<T>(resp: &hyper::Response<T>) -> Option<Duration> {
+    let seconds = resp
+        .headers()
+        .get(hyper::header::RETRY_AFTER)?
+        .to_str()
+        .ok()?
+        .trim()
+        .parse::<u64>()
+        .ok()?;
+    // cap a server-supplied delay so a bogus value can't pin a slot for hours
+    Some(Duration::from_secs(seconds.min(60)))
+}
+
 fn write_file(base_path: &Path, message_name: &str, message_content: &[u8]) -> Result<()> {
     let path = base_path.join(".git").join(message_name);
     let path_parent = path
@@ -158,17 +701,32 @@ fn queue_new_references(
     tx: UnboundedSender<DownloadedFile>,
 ) -> Result<()> {
     match name {
-        "HEAD" | "refs/remotes/origin/HEAD" => {
-            let ref_path = parse_head(content)?;
-            println!("\tFound ref path {ref_path}");
-
-            tx.send(DownloadedFile {
-                path: ref_path.into(),
-                tx: tx.clone(),
-            })
-            .unwrap();
+        n if (n == "HEAD" || n.ends_with("/HEAD")) && !n.starts_with("logs/") => {
+            match parse_head(content) {
+                Ok(ref_path) => {
+                    println!("\tFound ref path {ref_path}");
+                    tx.send(DownloadedFile {
+                        path: ref_path.into(),
+                        tx: tx.clone(),
+                    })
+                    .unwrap();
+                }
+                // some servers store a bare commit hash here instead of a symref
+                Err(_) => {
+                    let hash = parse_hash(content)?;
+                    println!("\tFound object hash {hash}");
+                    tx.send(DownloadedFile {
+                        path: hash_to_url(hash),
+                        tx: tx.clone(),
+                    })
+                    .unwrap();
+                }
+            }
         }
-        n if n.starts_with("refs/heads/") || n == "ORIG_HEAD" => {
+        n if n.starts_with("refs/heads/")
+            || n.starts_with("refs/remotes/")
+            || n == "ORIG_HEAD" =>
+        {
             let hash = parse_hash(content)?;
             println!("\tFound object hash {hash}");
 
@@ -178,8 +736,61 @@ fn queue_new_references(
             })
             .unwrap();
         }
+        "index" => {
+            let entries = parse_index(content)?;
+            println!("\tFound index with {} tracked blobs", entries.len());
+            for entry in entries {
+                tx.send(DownloadedFile {
+                    path: hash_to_url(&entry.hash),
+                    tx: tx.clone(),
+                })
+                .unwrap();
+            }
+        }
+        "packed-refs" => {
+            let hashes = parse_packed_refs(content)?;
+            println!("\tFound packed-refs with {} hashes", hashes.len());
+            for hash in hashes {
+                tx.send(DownloadedFile {
+                    path: hash_to_url(&hash),
+                    tx: tx.clone(),
+                })
+                .unwrap();
+            }
+        }
+        "config" => {
+            let refs = parse_config(content)?;
+            println!(
+                "\tFound config with {} remote(s) and {} branch(es)",
+                refs.remotes.len(),
+                refs.branches.len()
+            );
+
+            // learn the real branch and remote names instead of assuming origin
+            for branch in refs.branches {
+                if !is_safe_ref_name(&branch) {
+                    println!("\tIgnoring unsafe branch name {branch:?}");
+                    continue;
+                }
+                tx.send(DownloadedFile {
+                    path: format!("refs/heads/{branch}"),
+                    tx: tx.clone(),
+                })
+                .unwrap();
+            }
+            for remote in refs.remotes {
+                if !is_safe_ref_name(&remote) {
+                    println!("\tIgnoring unsafe remote name {remote:?}");
+                    continue;
+                }
+                tx.send(DownloadedFile {
+                    path: format!("refs/remotes/{remote}/HEAD"),
+                    tx: tx.clone(),
+                })
+                .unwrap();
+            }
+        }
         // TODO: handle FETCH_HEAD, detect branches
-        // TODO: handle config, detect branches
         n if n.starts_with("logs/") => {
             let hashes = parse_log(content)?;
 
@@ -192,16 +803,57 @@ fn queue_new_references(
                 .unwrap();
             }
         }
+        "objects/info/packs" => {
+            let content = String::from_utf8_lossy(content);
+            for line in content.lines() {
+                // lines look like `P pack-<sha>.pack`
+                if let Some(pack_name) = line.strip_prefix("P ") {
+                    let idx_name = pack_name.trim_end().replace(".pack", ".idx");
+                    println!("\tFound pack {pack_name}");
+                    tx.send(DownloadedFile {
+                        path: format!("objects/pack/{idx_name}"),
+                        tx: tx.clone(),
+                    })
+                    .unwrap();
+                }
+            }
+        }
+        n if n.ends_with(".idx") => {
+            // the index is only used for the log line below; a truncated or
+            // legacy-v1 `.idx` must not stop us from fetching the pack it names,
+            // which is where the recoverable history actually lives
+            match crate::packfile::parse_idx(content) {
+                Ok(hashes) => println!("\tFound pack index listing {} objects", hashes.len()),
+                Err(e) => println!("\tCould not parse pack index ({e}), fetching pack anyway"),
+            }
+
+            tx.send(DownloadedFile {
+                path: n.replace(".idx", ".pack"),
+                tx: tx.clone(),
+            })
+            .unwrap();
+        }
+        n if n.ends_with(".pack") => {
+            let hashes = crate::packfile::parse_pack(content)?;
+            println!("\tFound packfile referencing {} objects", hashes.len());
+            for hash in hashes {
+                tx.send(DownloadedFile {
+                    path: hash_to_url(&hash),
+                    tx: tx.clone(),
+                })
+                .unwrap();
+            }
+        }
         n if n.starts_with("objects/") && REGEX_OBJECT_PATH.is_match(n) => {
             match parse_object(content)? {
                 GitObject::Blob => {
                     println!("\tFound blob object");
                 }
-                GitObject::Tree(hashes) => {
-                    println!("\tFound tree object with {} hashes", hashes.len());
-                    for hash in hashes {
+                GitObject::Tree(entries) => {
+                    println!("\tFound tree object with {} entries", entries.len());
+                    for entry in entries {
                         tx.send(DownloadedFile {
-                            path: hash_to_url(&hash),
+                            path: hash_to_url(&entry.hash),
                             tx: tx.clone(),
                         })
                         .unwrap();
@@ -226,6 +878,15 @@ fn queue_new_references(
     Ok(())
 }
 
+/// Whether a config-derived remote/branch name is safe to interpolate into a
+/// ref path: no traversal segments and no whitespace or control characters
+/// that would corrupt the request URL.
+fn is_safe_ref_name(name: &str) -> bool {
+    check_path_traversal(name).is_ok()
+        && !name.is_empty()
+        && !name.chars().any(|c| c.is_whitespace() || c.is_control())
+}
+
 fn hash_to_url(hash: &str) -> String {
     assert_eq!(hash.len(), 40);
     let hash_start = &hash[0..2];
@@ -5,17 +5,26 @@ use std::{collections::HashSet, fmt::Write};
 
 lazy_static::lazy_static! {
     static ref REGEX_HASH: Regex = Regex::new(r"^[a-f\d]{40}$").unwrap();
-    static ref REGEX_REFS_PATH: Regex = Regex::new(r"^refs/heads/(\S+)$").unwrap();
+    static ref REGEX_REFS_PATH: Regex = Regex::new(r"^refs/(heads|remotes)/(\S+)$").unwrap();
+    static ref REGEX_CONFIG_SECTION: Regex = Regex::new(r#"^\[(remote|branch)\s+"([^"]+)"\]$"#).unwrap();
 }
 
 const EMPTY_HASH: &str = "0000000000000000000000000000000000000000";
 
 pub enum GitObject {
-    Tree(Vec<String>),
+    Tree(Vec<TreeEntry>),
     Commit(Vec<String>),
     Blob,
 }
 
+/// A single entry of a tree object: its octal file mode, the entry name and the
+/// hash of the object it points at.
+pub struct TreeEntry {
+    pub mode: String,
+    pub name: String,
+    pub hash: String,
+}
+
 pub fn parse_head(data: &[u8]) -> Result<&str> {
     let content = std::str::from_utf8(data)?;
 
@@ -31,16 +40,26 @@ pub fn parse_head(data: &[u8]) -> Result<&str> {
 
     // check for potential path traversal
     // a normal git setup should never emit paths with `..` segments
-    if content.split(['/', '\\']).any(|segment| segment == "..") {
-        bail!(
-            "Unexpected path traversal detected in HEAD file: {}",
-            content
-        );
-    }
+    check_path_traversal(content)?;
 
     Ok(content)
 }
 
+/// Reject paths containing `..` segments.
+///
+/// A normal git setup should never emit such paths, so encountering one means
+/// a malicious server is trying to make us write outside the output directory.
+pub fn check_path_traversal(path: &str) -> Result<()> {
+    // an absolute path would make `Path::join` discard the output directory
+    if path.starts_with('/') || path.starts_with('\\') {
+        bail!("Unexpected absolute path detected: {}", path);
+    }
+    if path.split(['/', '\\']).any(|segment| segment == "..") {
+        bail!("Unexpected path traversal detected: {}", path);
+    }
+    Ok(())
+}
+
 pub fn parse_hash(data: &[u8]) -> Result<&str> {
     let content = std::str::from_utf8(data)?;
     let content = content.trim_end();
@@ -59,30 +78,72 @@ pub fn parse_object(data: &[u8]) -> Result<GitObject> {
         [b't', b'r', b'e', b'e', _, _] => {
             let decompressed = miniz_oxide::inflate::decompress_to_vec_zlib(data)
                 .map_err(|e| anyhow!("Problem while decompressing git object: {}", e))?;
-            let decompressed = decompressed.as_slice();
-
-            let mut hashes = vec![];
-
-            // TODO: this is ugly, use a slice-based approach instead
-            let mut decompressed_iter = split_object_at_zero(decompressed)?.iter().peekable();
-            while decompressed_iter.peek().is_some() {
-                let bytes: Vec<u8> = (&mut decompressed_iter)
-                    .skip_while(|&&b| b != b'\0')
-                    .skip(1)
-                    .take(0x14)
-                    .cloned()
-                    .collect();
-                hashes.push(slice_to_hex(&bytes));
-            }
 
-            Ok(GitObject::Tree(hashes))
+            parse_object_body("tree", split_object_at_zero(&decompressed)?)
         }
         [b'c', b'o', b'm', b'm', b'i', b't'] => {
             let decompressed = miniz_oxide::inflate::decompress_to_vec_zlib(data)
                 .map_err(|e| anyhow!("Problem while decompressing git object: {}", e))?;
 
-            let decompressed = split_object_at_zero(&decompressed)?;
-            let commit_message = String::from_utf8_lossy(decompressed);
+            parse_object_body("commit", split_object_at_zero(&decompressed)?)
+        }
+        [b't', b'a', b'g', b' ', _, _] => {
+            let decompressed = miniz_oxide::inflate::decompress_to_vec_zlib(data)
+                .map_err(|e| anyhow!("Problem while decompressing git object: {}", e))?;
+
+            parse_object_body("tag", split_object_at_zero(&decompressed)?)
+        }
+        _ => bail!(
+            "Unknown git object header: {}",
+            String::from_utf8_lossy(&peek)
+        ),
+    }
+}
+
+/// Parse the *body* of a git object (the content after the `"<type> <len>\0"`
+/// header), given its type keyword.
+///
+/// Loose objects carry that header and are dispatched here by [`parse_object`],
+/// while packfile entries store the body on its own; both share this routine so
+/// tree and commit references are discovered identically regardless of origin.
+pub fn parse_object_body(kind: &str, body: &[u8]) -> Result<GitObject> {
+    match kind {
+        "blob" => Ok(GitObject::Blob),
+        "tree" => {
+            // each entry is `"<octal mode> <name>\0"` followed by the 20 raw
+            // bytes of the referenced object's hash
+            let mut entries = vec![];
+            let mut rest = body;
+            while !rest.is_empty() {
+                let space = rest
+                    .iter()
+                    .position(|&b| b == b' ')
+                    .ok_or_else(|| anyhow!("Malformed tree entry, missing mode separator"))?;
+                let mode = std::str::from_utf8(&rest[..space])?.to_string();
+                rest = &rest[space + 1..];
+
+                let nul = rest
+                    .iter()
+                    .position(|&b| b == b'\0')
+                    .ok_or_else(|| anyhow!("Malformed tree entry, missing name terminator"))?;
+                // filenames are not guaranteed to be UTF-8, so don't reject
+                // the whole tree over one exotic name
+                let name = String::from_utf8_lossy(&rest[..nul]).into_owned();
+                rest = &rest[nul + 1..];
+
+                let hash_bytes = rest
+                    .get(..0x14)
+                    .ok_or_else(|| anyhow!("Malformed tree entry, hash is truncated"))?;
+                let hash = slice_to_hex(hash_bytes);
+                rest = &rest[0x14..];
+
+                entries.push(TreeEntry { mode, name, hash });
+            }
+
+            Ok(GitObject::Tree(entries))
+        }
+        "commit" => {
+            let commit_message = String::from_utf8_lossy(body);
 
             let hashes = commit_message
                 .lines()
@@ -96,11 +157,200 @@ pub fn parse_object(data: &[u8]) -> Result<GitObject> {
 
             Ok(GitObject::Commit(hashes))
         }
-        _ => bail!(
-            "Unknown git object header: {}",
-            String::from_utf8_lossy(&peek)
-        ),
+        "tag" => {
+            let tag = String::from_utf8_lossy(body);
+
+            let hashes = tag
+                .lines()
+                .take_while(|&line| !line.trim().is_empty())
+                .filter_map(|line| match line.split_once(' ') {
+                    Some(("object", hash)) => Some(hash.into()),
+                    _ => None,
+                })
+                .collect();
+
+            Ok(GitObject::Commit(hashes))
+        }
+        _ => bail!("Unknown git object type: {kind}"),
+    }
+}
+
+/// The remote and branch names discovered from a git `config` file.
+pub struct ConfigRefs {
+    pub remotes: Vec<String>,
+    pub branches: Vec<String>,
+}
+
+pub fn parse_packed_refs(data: &[u8]) -> Result<Vec<String>> {
+    let content = String::from_utf8_lossy(data);
+
+    let mut hashes = vec![];
+    for line in content.lines() {
+        let line = line.trim();
+
+        // skip comments and `^`-prefixed peeled-tag lines
+        if line.is_empty() || line.starts_with('#') || line.starts_with('^') {
+            continue;
+        }
+
+        if let Some((hash, _refname)) = line.split_once(' ') {
+            if REGEX_HASH.is_match(hash) && hash != EMPTY_HASH {
+                hashes.push(hash.to_string());
+            }
+        }
+    }
+
+    Ok(hashes)
+}
+
+pub fn parse_config(data: &[u8]) -> Result<ConfigRefs> {
+    let content = String::from_utf8_lossy(data);
+
+    let mut remotes = vec![];
+    let mut branches = vec![];
+    for line in content.lines() {
+        if let Some(captures) = REGEX_CONFIG_SECTION.captures(line.trim()) {
+            let name = captures[2].to_string();
+            match &captures[1] {
+                "remote" => remotes.push(name),
+                "branch" => branches.push(name),
+                _ => unreachable!("regex only matches remote and branch"),
+            }
+        }
+    }
+
+    Ok(ConfigRefs { remotes, branches })
+}
+
+/// A single entry of the dircache index: a tracked path and the blob it points
+/// at.
+pub struct IndexEntry {
+    pub path: String,
+    pub hash: String,
+    /// The raw 32-bit mode word, so symlinks and executables can be restored.
+    pub mode: u32,
+}
+
+/// Parse a `.git/index` (dircache) file into its tracked `(path, blob hash)`
+/// entries.
+///
+/// The file starts with the `DIRC` signature, a 4-byte version and a 4-byte
+/// entry count; each entry is a fixed 62-byte header (the stat fields, a 20-byte
+/// object id and a 16-bit flags field whose low 12 bits give the name length)
+/// followed by the NUL-padded path. Versions 3 and 4 add an extended flags word
+/// and path-prefix compression respectively, both of which are handled here.
+pub fn parse_index(data: &[u8]) -> Result<Vec<IndexEntry>> {
+    if data.len() < 12 || &data[0..4] != b"DIRC" {
+        bail!("Index file is missing the DIRC signature");
+    }
+    let version = u32::from_be_bytes([data[4], data[5], data[6], data[7]]);
+    if !(2..=4).contains(&version) {
+        bail!("Unsupported index version: {version}");
+    }
+    let count = u32::from_be_bytes([data[8], data[9], data[10], data[11]]) as usize;
+
+    let mut entries = Vec::with_capacity(count.min(1 << 16));
+    let mut cursor = 12;
+    // keep the previous path as raw bytes so v4 prefix stripping never slices
+    // across a UTF-8 boundary
+    let mut prev_path: Vec<u8> = Vec::new();
+    for _ in 0..count {
+        let entry_start = cursor;
+
+        let header = data
+            .get(cursor..cursor + 62)
+            .ok_or_else(|| anyhow!("Index entry header is truncated"))?;
+        let mode = u32::from_be_bytes([header[24], header[25], header[26], header[27]]);
+        let hash = slice_to_hex(&header[40..60]);
+        let flags = u16::from_be_bytes([header[60], header[61]]);
+        let name_len = (flags & 0x0fff) as usize;
+        let extended = flags & 0x4000 != 0;
+        cursor += 62;
+
+        // version 3+ stores an extra 16-bit flags word when the extended bit is set
+        if extended {
+            cursor += 2;
+        }
+
+        let path_bytes = if version >= 4 {
+            // path-prefix compression: strip N bytes from the previous path and
+            // append the NUL-terminated suffix that follows
+            let rest = data
+                .get(cursor..)
+                .ok_or_else(|| anyhow!("Index entry is truncated"))?;
+            let (strip, varint_len) = read_index_varint(rest)?;
+            cursor += varint_len;
+
+            let rest = data
+                .get(cursor..)
+                .ok_or_else(|| anyhow!("Index entry is truncated"))?;
+            let nul = rest
+                .iter()
+                .position(|&b| b == b'\0')
+                .ok_or_else(|| anyhow!("Index entry name is not NUL-terminated"))?;
+            let suffix = &rest[..nul];
+            cursor += nul + 1;
+
+            let kept = prev_path
+                .len()
+                .checked_sub(strip)
+                .ok_or_else(|| anyhow!("Index path prefix strip underflows"))?;
+            let mut path = prev_path[..kept].to_vec();
+            path.extend_from_slice(suffix);
+            path
+        } else {
+            // versions 2 and 3: the name runs for `name_len` bytes (or up to the
+            // NUL when it does not fit in the 12-bit field) and the entry is then
+            // NUL-padded to a multiple of eight bytes
+            let name_bytes = if name_len < 0x0fff {
+                let bytes = data
+                    .get(cursor..cursor + name_len)
+                    .ok_or_else(|| anyhow!("Index entry name is truncated"))?;
+                cursor += name_len;
+                bytes
+            } else {
+                let rest = data
+                    .get(cursor..)
+                    .ok_or_else(|| anyhow!("Index entry is truncated"))?;
+                let nul = rest
+                    .iter()
+                    .position(|&b| b == b'\0')
+                    .ok_or_else(|| anyhow!("Index entry name is not NUL-terminated"))?;
+                cursor += nul;
+                &rest[..nul]
+            };
+
+            // pad (including the name's NUL terminator) up to the 8-byte boundary
+            let entry_len = cursor - entry_start;
+            cursor += 8 - (entry_len % 8);
+            name_bytes.to_vec()
+        };
+
+        let path = String::from_utf8_lossy(&path_bytes).into_owned();
+        prev_path = path_bytes;
+        entries.push(IndexEntry { path, hash, mode });
+    }
+
+    Ok(entries)
+}
+
+/// Read git's variable-width integer as used by index v4 path compression (the
+/// same "offset" encoding the packfile format uses for ofs-deltas).
+fn read_index_varint(data: &[u8]) -> Result<(usize, usize)> {
+    let mut idx = 0;
+    let mut byte = *data
+        .get(idx)
+        .ok_or_else(|| anyhow!("Index varint is truncated"))?;
+    let mut value = (byte & 0x7f) as usize;
+    idx += 1;
+    while byte & 0x80 != 0 {
+        byte = *data
+            .get(idx)
+            .ok_or_else(|| anyhow!("Index varint is truncated"))?;
+        value = ((value + 1) << 7) | (byte & 0x7f) as usize;
+        idx += 1;
     }
+    Ok((value, idx))
 }
 
 pub fn parse_log(data: &[u8]) -> Result<HashSet<String>> {
@@ -150,7 +400,7 @@ fn split_object_at_zero(data: &[u8]) -> Result<&[u8]> {
     Ok(data)
 }
 
-fn slice_to_hex(data: &[u8]) -> String {
+pub(crate) fn slice_to_hex(data: &[u8]) -> String {
     let mut s = String::with_capacity(data.len() * 2);
     for byte in data {
         write!(s, "{:02x}", byte).expect("writing hex should not fail");
@@ -176,8 +426,9 @@ mod tests {
         assert!(matches!(parsed, GitObject::Tree(_)));
 
         if let GitObject::Tree(vec) = parsed {
+            let hashes: Vec<String> = vec.iter().map(|entry| entry.hash.clone()).collect();
             assert_eq!(
-                vec,
+                hashes,
                 vec![
                     "93748a31e8df89b80ab5ebe4ad19ea62899a28fa".to_string(),
                     "920512d27e4df0c79ca4a929bc5d4254b3d05c4c".to_string(),
@@ -203,4 +454,30 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn parse_index_v2() {
+        let bytes = include_bytes!("../test-data/index-v2");
+        let entries = parse_index(bytes).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt", "run.sh", "sub/b.txt"]);
+
+        let run = entries.iter().find(|e| e.path == "run.sh").unwrap();
+        assert_eq!(run.hash, "4163036efa65bd4a469e752267498f01ea36a55c");
+        // run.sh is executable, so the mode must keep its owner-exec bit
+        assert_ne!(run.mode & 0o111, 0);
+    }
+
+    #[test]
+    fn parse_index_v4() {
+        // version 4 compresses each path against the previous one; the decoded
+        // entries must match the v2 layout exactly
+        let bytes = include_bytes!("../test-data/index-v4");
+        let entries = parse_index(bytes).unwrap();
+        let paths: Vec<&str> = entries.iter().map(|e| e.path.as_str()).collect();
+        assert_eq!(paths, vec!["a.txt", "run.sh", "sub/b.txt"]);
+
+        let nested = entries.iter().find(|e| e.path == "sub/b.txt").unwrap();
+        assert_eq!(nested.hash, "1c59427adc4b205a270d8f810310394962e79a8b");
+    }
 }
@@ -1,9 +1,22 @@
 use std::{path::PathBuf, sync::Arc};
 
-use clap::Parser;
+use clap::{Parser, ValueEnum};
 
 mod dump_git;
 mod git_parsing;
+mod packfile;
+mod smart_http;
+
+/// The strategy used to recover the repository.
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mode {
+    /// Download raw `.git` files served by an exposed directory listing.
+    Dumb,
+    /// Use the `git-upload-pack` smart-HTTP endpoint, like `git clone`.
+    Smart,
+    /// Try the smart endpoint first and fall back to the dumb crawler.
+    Auto,
+}
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
@@ -21,6 +34,18 @@ pub struct Args {
     /// Sets the maximum of concurrent download tasks that can be running
     #[arg(short, long, default_value_t = 8)]
     tasks: u16,
+
+    /// Selects how the repository is recovered
+    #[arg(short, long, value_enum, default_value_t = Mode::Auto)]
+    mode: Mode,
+
+    /// How many times to retry a download on a transient failure
+    #[arg(long, default_value_t = 3)]
+    retries: u32,
+
+    /// Base delay in milliseconds for the exponential retry backoff
+    #[arg(long, default_value_t = 500)]
+    retry_backoff_ms: u64,
 }
 
 #[tokio::main]
@@ -0,0 +1,513 @@
+use anyhow::{anyhow, bail, Result};
+
+use crate::git_parsing::{parse_object_body, slice_to_hex, GitObject};
+
+/// The object type tags used in a packfile entry header.
+///
+/// Types 1-4 are the "base" object types and map directly onto the loose
+/// object types understood by [`parse_object_body`]. Types 6 and 7 are deltas
+/// whose base is, respectively, an earlier entry in the same pack or an
+/// arbitrary object referenced by its SHA-1.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PackObjectType {
+    Commit,
+    Tree,
+    Blob,
+    Tag,
+    OfsDelta,
+    RefDelta,
+}
+
+impl PackObjectType {
+    fn from_tag(tag: u8) -> Result<Self> {
+        Ok(match tag {
+            1 => PackObjectType::Commit,
+            2 => PackObjectType::Tree,
+            3 => PackObjectType::Blob,
+            4 => PackObjectType::Tag,
+            6 => PackObjectType::OfsDelta,
+            7 => PackObjectType::RefDelta,
+            other => bail!("Unknown packfile object type: {other}"),
+        })
+    }
+
+    /// The loose-object type keyword for a base object type, or `None` for
+    /// deltas (which carry no type of their own until resolved).
+    fn base_keyword(self) -> Option<&'static str> {
+        match self {
+            PackObjectType::Commit => Some("commit"),
+            PackObjectType::Tree => Some("tree"),
+            PackObjectType::Blob => Some("blob"),
+            PackObjectType::Tag => Some("tag"),
+            _ => None,
+        }
+    }
+}
+
+/// A single reconstructed packfile object, after any delta chain has been
+/// resolved back to a plain base object.
+struct ResolvedObject {
+    ty: PackObjectType,
+    data: Vec<u8>,
+}
+
+/// An entry as it sits in the pack before its delta chain is resolved.
+enum RawEntry {
+    Base { ty: PackObjectType, data: Vec<u8> },
+    OfsDelta { base_offset: usize, delta: Vec<u8> },
+    RefDelta { base_hash: String, delta: Vec<u8> },
+}
+
+/// Parse a v2 pack index and return the SHA-1 of every object it lists.
+///
+/// The layout is: the 4-byte magic `\377tOc`, a 4-byte version (`2`), a
+/// 256-entry fan-out table of big-endian `u32`s (where `fanout[255]` is the
+/// total object count), then `N` raw 20-byte object names, `N` CRC-32 values,
+/// `N` 4-byte offsets and an optional table of 8-byte large offsets.
+pub fn parse_idx(data: &[u8]) -> Result<Vec<String>> {
+    if data.len() < 8 || &data[0..4] != b"\xfftOc" {
+        bail!("Packfile index is missing the v2 magic number");
+    }
+    let version = read_u32(data, 4)?;
+    if version != 2 {
+        bail!("Unsupported packfile index version: {version}");
+    }
+
+    let count = read_u32(data, 8 + 255 * 4)? as usize;
+
+    // the object names start right after the 4-byte magic, 4-byte version and
+    // the 256-entry fan-out table
+    let names_start = 8 + 256 * 4;
+    let mut hashes = Vec::with_capacity(count);
+    for i in 0..count {
+        let start = names_start + i * 20;
+        let end = start + 20;
+        let raw = data
+            .get(start..end)
+            .ok_or_else(|| anyhow!("Packfile index is truncated in the object name table"))?;
+        hashes.push(slice_to_hex(raw));
+    }
+
+    Ok(hashes)
+}
+
+/// Decode a packfile, resolving every delta, and return the list of object
+/// hashes that its commits and trees reference so they can be queued for
+/// download.
+///
+/// The pack starts with the `"PACK"` signature, a 4-byte version and a 4-byte
+/// object count; each entry is a variable-length header followed by zlib data.
+pub fn parse_pack(data: &[u8]) -> Result<Vec<String>> {
+    Ok(decode_pack(data)?.referenced)
+}
+
+/// A single base object reconstructed out of a packfile.
+pub struct PackObject {
+    pub hash: String,
+    /// The loose-object type keyword (`commit`/`tree`/`blob`/`tag`).
+    pub kind: &'static str,
+    pub data: Vec<u8>,
+}
+
+/// The full result of decoding a packfile: every reconstructed object plus the
+/// hashes they reference that are not themselves contained in the pack.
+pub struct DecodedPack {
+    pub objects: Vec<PackObject>,
+    pub referenced: Vec<String>,
+}
+
+/// Decode a packfile, resolving every delta, and return both the reconstructed
+/// objects and the external hashes they reference.
+pub fn decode_pack(data: &[u8]) -> Result<DecodedPack> {
+    if data.len() < 12 || &data[0..4] != b"PACK" {
+        bail!("Packfile is missing the \"PACK\" signature");
+    }
+    let version = read_u32(data, 4)?;
+    if version != 2 && version != 3 {
+        bail!("Unsupported packfile version: {version}");
+    }
+    let count = read_u32(data, 8)? as usize;
+
+    // the object count is server-controlled, so don't pre-allocate for it
+    // directly; the loop below errors out naturally if it overruns the data
+    let hint = count.min(1 << 16);
+
+    // first pass: read every entry in order without trying to resolve deltas,
+    // recording each one against its starting offset
+    let mut order = Vec::with_capacity(hint);
+    let mut raw: std::collections::HashMap<usize, RawEntry> =
+        std::collections::HashMap::with_capacity(hint);
+
+    let mut cursor = 12;
+    for _ in 0..count {
+        let entry_offset = cursor;
+        let (ty, _size, header_len) = parse_entry_header(&data[cursor..])?;
+        cursor += header_len;
+
+        let entry = match ty {
+            PackObjectType::OfsDelta => {
+                let (back, varint_len) = parse_offset_varint(&data[cursor..])?;
+                cursor += varint_len;
+                let base_offset = entry_offset
+                    .checked_sub(back)
+                    .ok_or_else(|| anyhow!("ofs-delta points before the start of the pack"))?;
+                let (delta, consumed) = inflate(&data[cursor..])?;
+                cursor += consumed;
+                RawEntry::OfsDelta { base_offset, delta }
+            }
+            PackObjectType::RefDelta => {
+                let base_hash = slice_to_hex(
+                    data.get(cursor..cursor + 20)
+                        .ok_or_else(|| anyhow!("ref-delta base hash is truncated"))?,
+                );
+                cursor += 20;
+                let (delta, consumed) = inflate(&data[cursor..])?;
+                cursor += consumed;
+                RawEntry::RefDelta { base_hash, delta }
+            }
+            base_ty => {
+                let (body, consumed) = inflate(&data[cursor..])?;
+                cursor += consumed;
+                RawEntry::Base { ty: base_ty, data: body }
+            }
+        };
+
+        order.push(entry_offset);
+        raw.insert(entry_offset, entry);
+    }
+
+    // second pass: resolve delta chains to a fixpoint. Bases always precede
+    // their deltas in practice, but we loop so that forward references inside
+    // the pack still resolve eventually.
+    let mut resolved: std::collections::HashMap<usize, ResolvedObject> =
+        std::collections::HashMap::with_capacity(hint);
+    let mut by_hash: std::collections::HashMap<String, usize> =
+        std::collections::HashMap::with_capacity(hint);
+
+    loop {
+        let mut progress = false;
+        for &offset in &order {
+            if resolved.contains_key(&offset) {
+                continue;
+            }
+            let obj = match &raw[&offset] {
+                RawEntry::Base { ty, data } => ResolvedObject {
+                    ty: *ty,
+                    data: data.clone(),
+                },
+                RawEntry::OfsDelta { base_offset, delta } => match resolved.get(base_offset) {
+                    Some(base) => ResolvedObject {
+                        ty: base.ty,
+                        data: apply_delta(&base.data, delta)?,
+                    },
+                    None => continue,
+                },
+                RawEntry::RefDelta { base_hash, delta } => {
+                    match by_hash.get(base_hash).and_then(|o| resolved.get(o)) {
+                        Some(base) => ResolvedObject {
+                            ty: base.ty,
+                            data: apply_delta(&base.data, delta)?,
+                        },
+                        None => continue,
+                    }
+                }
+            };
+
+            by_hash.insert(hash_object(&obj), offset);
+            resolved.insert(offset, obj);
+            progress = true;
+        }
+
+        if resolved.len() == count || !progress {
+            break;
+        }
+    }
+
+    // collect the hashes referenced by the reconstructed objects, then drop
+    // any that live in this very pack (they are already on disk) and any
+    // still-unresolved delta bases (those do need to be fetched separately)
+    let in_pack: std::collections::HashSet<String> = by_hash.keys().cloned().collect();
+    let mut referenced = vec![];
+    for offset in &order {
+        if let Some(obj) = resolved.get(offset) {
+            collect_references(obj, &mut referenced)?;
+        } else if let RawEntry::RefDelta { base_hash, .. } = &raw[offset] {
+            referenced.push(base_hash.clone());
+        }
+    }
+    referenced.retain(|hash| !in_pack.contains(hash));
+
+    // hand back the reconstructed base objects so callers can explode them to
+    // loose objects on disk
+    let mut objects = Vec::with_capacity(resolved.len());
+    for (hash, offset) in by_hash {
+        if let Some(obj) = resolved.remove(&offset) {
+            let kind = obj.ty.base_keyword().unwrap_or("blob");
+            objects.push(PackObject {
+                hash,
+                kind,
+                data: obj.data,
+            });
+        }
+    }
+
+    Ok(DecodedPack { objects, referenced })
+}
+
+/// Feed a reconstructed base object through the existing [`GitObject`]
+/// machinery and push any hashes it references.
+fn collect_references(obj: &ResolvedObject, referenced: &mut Vec<String>) -> Result<()> {
+    let Some(keyword) = obj.ty.base_keyword() else {
+        bail!("Cannot collect references from an unresolved delta");
+    };
+
+    match parse_object_body(keyword, &obj.data)? {
+        GitObject::Blob => {}
+        GitObject::Tree(entries) => referenced.extend(entries.into_iter().map(|e| e.hash)),
+        GitObject::Commit(hashes) => referenced.extend(hashes),
+    }
+
+    Ok(())
+}
+
+/// Compute the SHA-1 of a reconstructed object so that later ref-deltas in the
+/// same pack can find it by name.
+fn hash_object(obj: &ResolvedObject) -> String {
+    use sha1::{Digest, Sha1};
+
+    let keyword = obj.ty.base_keyword().unwrap_or("blob");
+    let mut hasher = Sha1::new();
+    hasher.update(format!("{keyword} {}\0", obj.data.len()).as_bytes());
+    hasher.update(&obj.data);
+    slice_to_hex(&hasher.finalize())
+}
+
+/// Parse a packfile entry header, returning the object type, its inflated size
+/// and the number of header bytes consumed.
+fn parse_entry_header(data: &[u8]) -> Result<(PackObjectType, usize, usize)> {
+    let first = *data.first().ok_or_else(|| anyhow!("Packfile entry header is empty"))?;
+    let ty = PackObjectType::from_tag((first >> 4) & 0b111)?;
+
+    let mut size = (first & 0b1111) as usize;
+    let mut shift = 4;
+    let mut idx = 1;
+    let mut byte = first;
+    while byte & 0b1000_0000 != 0 {
+        byte = *data
+            .get(idx)
+            .ok_or_else(|| anyhow!("Packfile entry header is truncated"))?;
+        if shift >= usize::BITS {
+            bail!("Packfile entry size varint is too long");
+        }
+        size |= ((byte & 0b0111_1111) as usize) << shift;
+        shift += 7;
+        idx += 1;
+    }
+
+    Ok((ty, size, idx))
+}
+
+/// Parse the negative-offset varint that follows an ofs-delta header. This uses
+/// git's "offset encoding", which is subtly different from the size varint.
+fn parse_offset_varint(data: &[u8]) -> Result<(usize, usize)> {
+    let mut idx = 0;
+    let mut byte = *data.get(idx).ok_or_else(|| anyhow!("ofs-delta offset is truncated"))?;
+    let mut value = (byte & 0b0111_1111) as usize;
+    idx += 1;
+    while byte & 0b1000_0000 != 0 {
+        byte = *data
+            .get(idx)
+            .ok_or_else(|| anyhow!("ofs-delta offset is truncated"))?;
+        value = ((value + 1) << 7) | (byte & 0b0111_1111) as usize;
+        idx += 1;
+    }
+    Ok((value, idx))
+}
+
+/// Apply a git delta to `base`, producing the reconstructed target object.
+///
+/// The delta begins with the source and target size varints, followed by a
+/// stream of instructions: a byte with the high bit set copies a run from the
+/// base (the low bits select which offset/size bytes follow), anything else is
+/// a literal insert of the next `n` bytes.
+fn apply_delta(base: &[u8], delta: &[u8]) -> Result<Vec<u8>> {
+    let mut cursor = 0;
+    let (_src_size, len) = parse_size_varint(&delta[cursor..])?;
+    cursor += len;
+    let (target_size, len) = parse_size_varint(&delta[cursor..])?;
+    cursor += len;
+
+    let mut out = Vec::with_capacity(target_size);
+    while cursor < delta.len() {
+        let instruction = delta[cursor];
+        cursor += 1;
+
+        if instruction & 0b1000_0000 != 0 {
+            // copy from base
+            let mut offset = 0usize;
+            for i in 0..4 {
+                if instruction & (1 << i) != 0 {
+                    let byte = *delta
+                        .get(cursor)
+                        .ok_or_else(|| anyhow!("delta copy offset is truncated"))?;
+                    offset |= (byte as usize) << (8 * i);
+                    cursor += 1;
+                }
+            }
+            let mut size = 0usize;
+            for i in 0..3 {
+                if instruction & (1 << (4 + i)) != 0 {
+                    let byte = *delta
+                        .get(cursor)
+                        .ok_or_else(|| anyhow!("delta copy size is truncated"))?;
+                    size |= (byte as usize) << (8 * i);
+                    cursor += 1;
+                }
+            }
+            if size == 0 {
+                size = 0x10000;
+            }
+            let end = offset
+                .checked_add(size)
+                .ok_or_else(|| anyhow!("delta copy range overflows"))?;
+            out.extend_from_slice(
+                base.get(offset..end)
+                    .ok_or_else(|| anyhow!("delta copy reads past the base object"))?,
+            );
+        } else if instruction != 0 {
+            // literal insert
+            let size = instruction as usize;
+            out.extend_from_slice(
+                delta
+                    .get(cursor..cursor + size)
+                    .ok_or_else(|| anyhow!("delta insert reads past the delta"))?,
+            );
+            cursor += size;
+        } else {
+            bail!("Encountered a reserved delta instruction (0x00)");
+        }
+    }
+
+    if out.len() != target_size {
+        bail!(
+            "Reconstructed delta target is {} bytes, expected {target_size}",
+            out.len()
+        );
+    }
+
+    Ok(out)
+}
+
+/// Parse a little-endian base-128 size varint, as used by the delta header.
+fn parse_size_varint(data: &[u8]) -> Result<(usize, usize)> {
+    let mut value = 0usize;
+    let mut shift = 0;
+    let mut idx = 0;
+    loop {
+        let byte = *data
+            .get(idx)
+            .ok_or_else(|| anyhow!("delta size varint is truncated"))?;
+        if shift >= usize::BITS {
+            bail!("delta size varint is too long");
+        }
+        value |= ((byte & 0b0111_1111) as usize) << shift;
+        idx += 1;
+        if byte & 0b1000_0000 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    Ok((value, idx))
+}
+
+/// Inflate a single zlib stream out of `data`, returning the decompressed bytes
+/// and how many compressed bytes were consumed so the caller can advance to the
+/// next entry.
+fn inflate(data: &[u8]) -> Result<(Vec<u8>, usize)> {
+    use miniz_oxide::inflate::core::{decompress, inflate_flags, DecompressorOxide};
+    use miniz_oxide::inflate::TINFLStatus;
+
+    let mut decomp = DecompressorOxide::new();
+    let mut out = vec![0u8; 32 * 1024];
+    let mut out_pos = 0;
+    let mut in_pos = 0;
+    let flags = inflate_flags::TINFL_FLAG_PARSE_ZLIB_HEADER;
+
+    loop {
+        if out_pos == out.len() {
+            out.resize(out.len() * 2, 0);
+        }
+        let (status, consumed, produced) = decompress(
+            &mut decomp,
+            &data[in_pos..],
+            &mut out,
+            out_pos,
+            flags | inflate_flags::TINFL_FLAG_HAS_MORE_INPUT,
+        );
+        in_pos += consumed;
+        out_pos += produced;
+        match status {
+            TINFLStatus::Done => {
+                out.truncate(out_pos);
+                return Ok((out, in_pos));
+            }
+            // the entry is truncated: we told the decoder more input might
+            // follow, but the pack has none left, so bail instead of spinning
+            TINFLStatus::NeedsMoreInput if in_pos >= data.len() => {
+                bail!("Packfile entry is truncated");
+            }
+            TINFLStatus::HasMoreOutput | TINFLStatus::NeedsMoreInput => continue,
+            other => bail!("Error while inflating packfile entry: {:?}", other),
+        }
+    }
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Result<u32> {
+    let bytes = data
+        .get(offset..offset + 4)
+        .ok_or_else(|| anyhow!("Unexpected end of data while reading a u32"))?;
+    Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn apply_delta_copy_and_insert() {
+        let base = b"hello, world!";
+        // src size (13), target size (19), then: copy [0..7], insert "brave ",
+        // copy [7..13]
+        let delta = [
+            0x0d, 0x13, // size varints
+            0x90, 0x07, // copy offset 0, size 7 -> "hello, "
+            0x06, b'b', b'r', b'a', b'v', b'e', b' ', // insert "brave "
+            0x91, 0x07, 0x06, // copy offset 7, size 6 -> "world!"
+        ];
+        let out = apply_delta(base, &delta).unwrap();
+        assert_eq!(out, b"hello, brave world!");
+    }
+
+    #[test]
+    fn parse_idx_lists_every_object() {
+        let bytes = include_bytes!("../test-data/sample.idx");
+        let hashes = parse_idx(bytes).unwrap();
+        assert_eq!(hashes.len(), 6);
+        assert!(hashes.contains(&"596cf24c9ff0b6af09f7f8f2286909de509d1f14".to_string()));
+    }
+
+    #[test]
+    fn decode_pack_reconstructs_objects() {
+        let bytes = include_bytes!("../test-data/sample.pack");
+        let decoded = decode_pack(bytes).unwrap();
+        assert_eq!(decoded.objects.len(), 6);
+
+        let blob = decoded
+            .objects
+            .iter()
+            .find(|o| o.hash == "3b18e512dba79e4c8300dd08aeb37f8e728b8dad")
+            .expect("a.txt blob should be reconstructed");
+        assert_eq!(blob.kind, "blob");
+        assert_eq!(blob.data, b"hello world\n");
+    }
+}
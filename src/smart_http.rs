@@ -0,0 +1,215 @@
+use anyhow::{anyhow, bail, Context, Result};
+use hyper::{Body, Method, Request, StatusCode};
+
+use crate::dump_git::SharedClient;
+
+/// A single advertised reference from the smart-HTTP ref discovery.
+#[derive(Debug)]
+pub struct AdvertisedRef {
+    pub hash: String,
+    pub name: String,
+}
+
+/// The result of the `git-upload-pack` ref advertisement: the refs the server
+/// exposes and the capability list it announced on the first ref line.
+#[derive(Debug)]
+pub struct Advertisement {
+    pub refs: Vec<AdvertisedRef>,
+    pub capabilities: Vec<String>,
+}
+
+/// Perform smart-HTTP ref discovery against `base_url` by requesting
+/// `info/refs?service=git-upload-pack` and parsing the pkt-line advertisement.
+pub async fn discover_refs(client: &SharedClient, base_url: &str) -> Result<Advertisement> {
+    let url = format!("{base_url}info/refs?service=git-upload-pack");
+
+    let resp = client
+        .get(url.parse().context("Invalid ref-discovery URL")?)
+        .await
+        .with_context(|| format!("Error while requesting {url}"))?;
+
+    if resp.status() != StatusCode::OK {
+        bail!("Ref discovery failed with status {}", resp.status());
+    }
+
+    let body = hyper::body::to_bytes(resp)
+        .await
+        .context("Could not read ref-discovery body")?;
+
+    parse_advertisement(&body)
+}
+
+/// Parse the pkt-line body returned by the ref advertisement, skipping the
+/// `# service=...` banner and extracting the `<sha> <refname>` pairs plus the
+/// capabilities that ride on the first ref line.
+fn parse_advertisement(data: &[u8]) -> Result<Advertisement> {
+    let lines = parse_pkt_lines(data)?;
+
+    let mut refs = vec![];
+    let mut capabilities = vec![];
+    for line in lines {
+        let line = String::from_utf8_lossy(&line);
+        let line = line.trim_end_matches('\n');
+
+        // the leading `# service=git-upload-pack` banner is not a ref
+        if line.starts_with('#') || line.is_empty() {
+            continue;
+        }
+
+        // the first ref line carries `<sha> <refname>\0<space-separated caps>`
+        let (ref_part, caps) = match line.split_once('\0') {
+            Some((ref_part, caps)) => (ref_part, Some(caps)),
+            None => (line, None),
+        };
+        if let Some(caps) = caps {
+            capabilities = caps.split(' ').map(|c| c.to_string()).collect();
+        }
+
+        if let Some((hash, name)) = ref_part.split_once(' ') {
+            // peeled tags (`refs/tags/x^{}`) point at the tag's target and are
+            // not separately requestable
+            if name.ends_with("^{}") {
+                continue;
+            }
+            refs.push(AdvertisedRef {
+                hash: hash.to_string(),
+                name: name.to_string(),
+            });
+        }
+    }
+
+    Ok(Advertisement { refs, capabilities })
+}
+
+/// Request a packfile for `wants` by POSTing to `git-upload-pack`, and return
+/// the raw packfile bytes (everything after the leading `NAK`/ACK pkt-lines).
+pub async fn fetch_pack(
+    client: &SharedClient,
+    base_url: &str,
+    wants: &[String],
+    capabilities: &[String],
+) -> Result<Vec<u8>> {
+    if wants.is_empty() {
+        bail!("Refusing to request an empty packfile");
+    }
+
+    // only advertise capabilities we actually understand; requesting
+    // side-band would force us to demultiplex the response stream
+    // deliberately omit `thin-pack` and `side-band*`: we want a self-contained
+    // pack and an un-multiplexed response stream
+    const SUPPORTED: &[&str] = &["multi_ack", "ofs-delta"];
+    let caps: Vec<&str> = capabilities
+        .iter()
+        .map(String::as_str)
+        .filter(|c| SUPPORTED.contains(c))
+        .collect();
+
+    let mut body = String::new();
+    for (i, want) in wants.iter().enumerate() {
+        if i == 0 && !caps.is_empty() {
+            body.push_str(&pkt_line(&format!("want {want} {}\n", caps.join(" "))));
+        } else {
+            body.push_str(&pkt_line(&format!("want {want}\n")));
+        }
+    }
+    body.push_str("0000"); // flush
+    body.push_str(&pkt_line("done\n"));
+
+    let url = format!("{base_url}git-upload-pack");
+    let request = Request::builder()
+        .method(Method::POST)
+        .uri(&url)
+        .header("content-type", "application/x-git-upload-pack-request")
+        .body(Body::from(body))
+        .context("Could not build git-upload-pack request")?;
+
+    let resp = client
+        .request(request)
+        .await
+        .with_context(|| format!("Error while requesting {url}"))?;
+    if resp.status() != StatusCode::OK {
+        bail!("git-upload-pack failed with status {}", resp.status());
+    }
+
+    let payload = hyper::body::to_bytes(resp)
+        .await
+        .context("Could not read git-upload-pack response")?;
+
+    strip_pack_preamble(&payload)
+}
+
+/// Skip the `NAK`/ACK pkt-lines that precede the packfile and return the raw
+/// `PACK...` bytes.
+fn strip_pack_preamble(data: &[u8]) -> Result<Vec<u8>> {
+    let mut cursor = 0;
+    while cursor + 4 <= data.len() {
+        // the packfile itself begins with "PACK" and is not pkt-line framed
+        if &data[cursor..cursor + 4] == b"PACK" {
+            return Ok(data[cursor..].to_vec());
+        }
+        let len = parse_pkt_len(&data[cursor..cursor + 4])?;
+        if len == 0 {
+            cursor += 4; // flush packet
+        } else if len < 4 || cursor + len > data.len() {
+            bail!("Malformed pkt-line length {len}");
+        } else {
+            cursor += len;
+        }
+    }
+    bail!("No packfile found in git-upload-pack response")
+}
+
+/// Split a pkt-line stream into its individual payloads, dropping flush (`0000`)
+/// packets.
+fn parse_pkt_lines(data: &[u8]) -> Result<Vec<Vec<u8>>> {
+    let mut lines = vec![];
+    let mut cursor = 0;
+    while cursor + 4 <= data.len() {
+        let len = parse_pkt_len(&data[cursor..cursor + 4])?;
+        if len == 0 {
+            cursor += 4; // flush packet
+            continue;
+        }
+        if len < 4 || cursor + len > data.len() {
+            bail!("Malformed pkt-line length {len}");
+        }
+        lines.push(data[cursor + 4..cursor + len].to_vec());
+        cursor += len;
+    }
+    Ok(lines)
+}
+
+fn parse_pkt_len(bytes: &[u8]) -> Result<usize> {
+    let text = std::str::from_utf8(bytes).context("pkt-line length is not valid ASCII")?;
+    usize::from_str_radix(text, 16).map_err(|e| anyhow!("Invalid pkt-line length: {e}"))
+}
+
+fn pkt_line(payload: &str) -> String {
+    format!("{:04x}{payload}", payload.len() + 4)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_advertisement_refs_and_caps() {
+        // a real `info/refs?service=git-upload-pack` body: service banner,
+        // flush, then the ref advertisement
+        let body = include_bytes!("../test-data/info-refs-advertisement");
+        let ad = parse_advertisement(body).unwrap();
+
+        // the `# service=...` banner must not be mistaken for a ref
+        assert!(ad.refs.iter().all(|r| !r.name.starts_with('#')));
+        assert!(ad
+            .capabilities
+            .contains(&"symref=HEAD:refs/heads/master".to_string()));
+
+        let master = ad
+            .refs
+            .iter()
+            .find(|r| r.name == "refs/heads/master")
+            .expect("master branch should be advertised");
+        assert_eq!(master.hash, "596cf24c9ff0b6af09f7f8f2286909de509d1f14");
+    }
+}